@@ -4,17 +4,28 @@
 //! They are using so many inodes and it is better to store them in database instead of
 //! filesystem. This module is adding files into database and retrieving them.
 
+use self::storage::StorageBackend;
 use crate::error::Result;
 use failure::err_msg;
+use log::error;
 use postgres::Connection;
-use rusoto_core::region::Region;
-use rusoto_credential::EnvironmentProvider;
-use rusoto_s3::{GetObjectRequest, PutObjectRequest, S3Client, S3};
 use rustc_serialize::json::{Json, ToJson};
 use std::fs;
-use std::io::Read;
+use std::io::{Read, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
 
+mod storage;
+
+pub use self::storage::local::LocalBackend;
+pub use self::storage::postgres::PostgresBackend;
+pub use self::storage::s3::S3Backend;
+pub use self::storage::{Blob, BlobMetadata, BlobOrRedirect};
+
+/// How many bytes of a file we sniff to guess its mime type. Reading the
+/// whole file just for this would defeat the point of streaming it into the
+/// storage backend afterwards.
+const MIME_SNIFF_BYTES: usize = 8 * 1024;
+
 fn get_file_list_from_dir<P: AsRef<Path>>(path: P, files: &mut Vec<PathBuf>) -> Result<()> {
     let path = path.as_ref();
 
@@ -50,81 +61,50 @@ pub fn get_file_list<P: AsRef<Path>>(path: P) -> Result<Vec<PathBuf>> {
     Ok(files)
 }
 
-pub struct Blob {
-    pub path: String,
-    pub mime: String,
-    pub date_updated: time::Timespec,
-    pub content: Vec<u8>,
+pub fn get_path(backend: &dyn StorageBackend, path: &str) -> Option<Blob> {
+    backend.get(path).ok()
 }
 
-pub fn get_path(conn: &Connection, path: &str) -> Option<Blob> {
-    let rows = conn
-        .query(
-            "SELECT path, mime, date_updated, content
-                           FROM files
-                           WHERE path = $1",
-            &[&path],
-        )
-        .unwrap();
-
-    if rows.len() == 0 {
-        None
-    } else {
-        let row = rows.get(0);
-        let mut content = row.get(3);
-        if content == b"in-s3" {
-            let client = s3_client();
-            content = client
-                .and_then(|c| {
-                    c.get_object(GetObjectRequest {
-                        bucket: "rust-docs-rs".into(),
-                        key: path.into(),
-                        ..Default::default()
-                    })
-                    .sync()
-                    .ok()
-                })
-                .and_then(|r| r.body)
-                .map(|b| {
-                    let mut b = b.into_blocking_read();
-                    let mut content = Vec::new();
-                    b.read_to_end(&mut content).unwrap();
-                    content
-                })
-                .unwrap();
+/// Like [`get_path`], but lets the web layer redirect the client straight to
+/// a presigned URL instead of streaming S3-backed content through the app.
+pub fn get_path_or_redirect(backend: &dyn StorageBackend, path: &str) -> Option<BlobOrRedirect> {
+    if let Some(url) = backend.get_public_url(path) {
+        // `get_public_url` just signs a URL; it doesn't know whether the
+        // object actually exists, so without this check a missing file would
+        // redirect to a 404 from S3 instead of a 404 from us.
+        return if backend.exists(path).unwrap_or(false) {
+            Some(BlobOrRedirect::Redirect(url))
+        } else {
+            None
         };
-
-        Some(Blob {
-            path: row.get(0),
-            mime: row.get(1),
-            date_updated: row.get(2),
-            content,
-        })
     }
+
+    backend.get(path).ok().map(BlobOrRedirect::Blob)
 }
 
-fn s3_client() -> Option<S3Client> {
-    // If AWS keys aren't configured, then presume we should use the DB exclusively
-    // for file storage.
-    if std::env::var_os("AWS_ACCESS_KEY_ID").is_none() {
-        return None;
-    }
-    Some(S3Client::new_with(
-        rusoto_core::request::HttpClient::new().unwrap(),
-        EnvironmentProvider::default(),
-        std::env::var("S3_ENDPOINT")
-            .ok()
-            .map(|e| Region::Custom {
-                name: "us-west-1".to_owned(),
-                endpoint: e,
-            })
-            .unwrap_or(Region::UsWest1),
-    ))
+/// Like [`get_path`], but returns the content as a stream instead of a fully
+/// buffered blob, so callers can pipe it straight to the HTTP response
+/// without an extra in-memory copy.
+pub fn get_path_stream(
+    backend: &dyn StorageBackend,
+    path: &str,
+) -> Result<(BlobMetadata, Box<dyn Read>)> {
+    backend.get_stream(path)
 }
 
-/// Adds files into database and returns list of files with their mime type in Json
+/// Adds files into the given storage backend and returns a list of the files
+/// with their mime type in Json.
+///
+/// Each file is written to `backend` with its own call, rather than inside a
+/// single transaction covering the whole directory: `backend` is generic over
+/// any [`StorageBackend`], and most of them (S3, local disk) have no
+/// transaction concept to share with the others, so a cross-backend
+/// transaction isn't something this function can offer in general. A crash
+/// partway through therefore leaves whichever files were already written in
+/// place; callers that need all-or-nothing semantics should re-upload the
+/// whole directory, since `put`/`put_stream` overwrite existing paths.
 pub fn add_path_into_database<P: AsRef<Path>>(
-    conn: &Connection,
+    backend: &dyn StorageBackend,
     prefix: &str,
     path: P,
 ) -> Result<Json> {
@@ -132,94 +112,59 @@ pub fn add_path_into_database<P: AsRef<Path>>(
     let cookie = Cookie::open(flags::MIME_TYPE)?;
     cookie.load::<&str>(&[])?;
 
-    let trans = conn.transaction()?;
-    let client = s3_client();
     let mut file_list_with_mimes: Vec<(String, PathBuf)> = Vec::new();
 
     for file_path in get_file_list(&path)? {
-        let (path, content, mime) = {
-            let path = Path::new(path.as_ref()).join(&file_path);
-            // Some files have insufficient permissions (like .lock file created by cargo in
-            // documentation directory). We are skipping this files.
-            let mut file = match fs::File::open(path) {
-                Ok(f) => f,
-                Err(_) => continue,
-            };
-            let mut content: Vec<u8> = Vec::new();
-            file.read_to_end(&mut content)?;
-            let bucket_path = Path::new(prefix)
-                .join(&file_path)
-                .into_os_string()
-                .into_string()
-                .unwrap();
-
-            let mime = {
-                let mime = cookie.buffer(&content)?;
-                // css's are causing some problem in browsers
-                // magic will return text/plain for css file types
-                // convert them to text/css
-                // do the same for javascript files
-                if mime == "text/plain" {
-                    let e = file_path.extension().unwrap_or_default();
-                    if e == "css" {
-                        "text/css".to_owned()
-                    } else if e == "js" {
-                        "application/javascript".to_owned()
-                    } else {
-                        mime.to_owned()
-                    }
+        let bucket_path = Path::new(prefix)
+            .join(&file_path)
+            .into_os_string()
+            .into_string()
+            .unwrap();
+
+        // Some files have insufficient permissions (like .lock file created by cargo in
+        // documentation directory). We are skipping this files.
+        let mut file = match fs::File::open(Path::new(path.as_ref()).join(&file_path)) {
+            Ok(f) => f,
+            Err(_) => continue,
+        };
+
+        // Sniff the mime type from just the first chunk of the file, then
+        // seek back to stream the whole thing into the backend, rather than
+        // buffering the entire file here too. `Read::read` may return fewer
+        // bytes than asked for well before EOF, so loop via `take` +
+        // `read_to_end` instead of trusting a single `read` call to fill the
+        // buffer.
+        let mut sniff_buf = Vec::new();
+        (&mut file)
+            .take(MIME_SNIFF_BYTES as u64)
+            .read_to_end(&mut sniff_buf)?;
+        file.seek(SeekFrom::Start(0))?;
+
+        let mime = {
+            let mime = cookie.buffer(&sniff_buf)?;
+            // css's are causing some problem in browsers
+            // magic will return text/plain for css file types
+            // convert them to text/css
+            // do the same for javascript files
+            if mime == "text/plain" {
+                let e = file_path.extension().unwrap_or_default();
+                if e == "css" {
+                    "text/css".to_owned()
+                } else if e == "js" {
+                    "application/javascript".to_owned()
                 } else {
                     mime.to_owned()
                 }
-            };
-
-            let content: Option<Vec<u8>> = if let Some(client) = &client {
-                let s3_res = client
-                    .put_object(PutObjectRequest {
-                        bucket: "rust-docs-rs".into(),
-                        key: bucket_path.clone(),
-                        body: Some(content.clone().into()),
-                        content_type: Some(mime.clone()),
-                        ..Default::default()
-                    })
-                    .sync();
-                match s3_res {
-                    // we've successfully uploaded the content, so steal it;
-                    // we don't want to put it in the DB
-                    Ok(_) => None,
-                    // Since s3 was configured, we want to panic on failure to upload.
-                    Err(e) => panic!("failed to upload to {}: {:?}", bucket_path, e),
-                }
             } else {
-                Some(content.clone().into())
-            };
-
-            file_list_with_mimes.push((mime.clone(), file_path.clone()));
-
-            (bucket_path, content, mime)
+                mime.to_owned()
+            }
         };
 
-        // check if file already exists in database
-        let rows = conn.query("SELECT COUNT(*) FROM files WHERE path = $1", &[&path])?;
-
-        let content = content.unwrap_or_else(|| "in-s3".to_owned().into());
+        backend.put_stream(&bucket_path, &mime, Box::new(file))?;
 
-        if rows.get(0).get::<usize, i64>(0) == 0 {
-            trans.query(
-                "INSERT INTO files (path, mime, content) VALUES ($1, $2, $3)",
-                &[&path, &mime, &content],
-            )?;
-        } else {
-            trans.query(
-                "UPDATE files SET mime = $2, content = $3, date_updated = NOW() \
-                 WHERE path = $1",
-                &[&path, &mime, &content],
-            )?;
-        }
+        file_list_with_mimes.push((mime, file_path));
     }
 
-    trans.commit()?;
-
     file_list_to_json(file_list_with_mimes)
 }
 
@@ -236,11 +181,36 @@ fn file_list_to_json(file_list: Vec<(String, PathBuf)>) -> Result<Json> {
     Ok(file_list_json.to_json())
 }
 
-pub fn move_to_s3(conn: &Connection, n: usize) -> Result<()> {
-    let trans = conn.transaction()?;
-    let client = s3_client().expect("configured s3");
+/// Outcome of a [`move_to_s3`] run.
+#[derive(Debug, Default)]
+pub struct MigrationSummary {
+    pub uploaded: usize,
+    pub failed: usize,
+    pub bytes_moved: u64,
+}
 
-    let rows = trans.query(
+/// Copies up to `n` rows still stored in the database over to `backend`,
+/// uploading up to `concurrency` files at once (16 is a reasonable default).
+///
+/// Each row whose upload succeeded is marked `in-s3` on its own, so a single
+/// failed upload doesn't stop the others from being recorded; re-running the
+/// migration will only retry the files still marked as being in the database.
+/// The rows are marked only after every upload of the run has finished, so a
+/// crash before that point records none of that run's uploads. Per-file
+/// errors are logged and counted in the returned summary's `failed` rather
+/// than aborting the whole run.
+///
+/// `conn` is never shared with the upload worker threads: `postgres::Connection`
+/// isn't `Sync`, so the workers only call `backend.put` and report their
+/// result back over a channel; `conn` is used solely by this function, on the
+/// thread that already owns it, once every upload has finished.
+pub fn move_to_s3(
+    conn: &Connection,
+    backend: &(dyn StorageBackend + Sync),
+    n: usize,
+    concurrency: usize,
+) -> Result<MigrationSummary> {
+    let rows = conn.query(
         &format!(
             "SELECT path, mime, content FROM files WHERE content != E'in-s3' LIMIT {}",
             n
@@ -248,45 +218,140 @@ pub fn move_to_s3(conn: &Connection, n: usize) -> Result<()> {
         &[],
     )?;
 
-    let mut rt = ::tokio::runtime::current_thread::Runtime::new().unwrap();
-    let mut futures = Vec::new();
+    let (sender, receiver) = crossbeam_channel::unbounded();
     for row in &rows {
         let path: String = row.get(0);
         let mime: String = row.get(1);
         let content: Vec<u8> = row.get(2);
-        let path_1 = path.clone();
-        futures.push(
-            client
-                .put_object(PutObjectRequest {
-                    bucket: "rust-docs-rs".into(),
-                    key: path.clone(),
-                    body: Some(content.into()),
-                    content_type: Some(mime),
-                    ..Default::default()
-                })
-                .map(move |_| path_1)
-                .map_err(move |e| panic!("failed to upload to {}: {:?}", path, e)),
+        sender.send((path, mime, content)).unwrap();
+    }
+    drop(sender);
+
+    let (result_sender, result_receiver) = crossbeam_channel::unbounded();
+
+    crossbeam_utils::thread::scope(|scope| {
+        for _ in 0..concurrency.max(1) {
+            let receiver = receiver.clone();
+            let result_sender = result_sender.clone();
+
+            scope.spawn(move |_| {
+                for (path, mime, content) in receiver {
+                    let bytes_moved = content.len() as u64;
+                    let uploaded = backend
+                        .put(&path, &mime, &content)
+                        .map_err(|e| e.to_string());
+                    result_sender.send((path, bytes_moved, uploaded)).unwrap();
+                }
+            });
+        }
+        drop(result_sender);
+    })
+    .map_err(|_| err_msg("a move_to_s3 worker thread panicked"))?;
+
+    let mut summary = MigrationSummary::default();
+    for (path, bytes_moved, uploaded) in result_receiver {
+        if let Err(e) = uploaded {
+            error!("failed to upload {} to s3: {}", path, e);
+            summary.failed += 1;
+            continue;
+        }
+
+        let mark_in_s3 = conn.execute(
+            "UPDATE files SET content = E'in-s3' WHERE path = $1",
+            &[&path],
         );
+        if let Err(e) = mark_in_s3 {
+            error!("failed to mark {} as in-s3: {}", path, e);
+            summary.failed += 1;
+            continue;
+        }
+
+        summary.uploaded += 1;
+        summary.bytes_moved += bytes_moved;
     }
 
-    use ::futures::future::Future;
-    match rt.block_on(::futures::future::join_all(futures)) {
-        Ok(paths) => {
-            let statement = trans
-                .prepare("UPDATE files SET content = E'in-s3' WHERE path = $1")
-                .unwrap();
-            for path in paths {
-                statement.execute(&[&path]).unwrap();
-            }
+    Ok(summary)
+}
+
+/// Outcome of a [`check_s3_consistency`] run.
+#[derive(Debug, Default)]
+pub struct ConsistencyReport {
+    /// Paths the database marks `in-s3` with no matching object in the bucket.
+    pub missing_from_bucket: Vec<String>,
+    /// Bucket objects with no matching `path` row in the database.
+    pub orphaned_in_bucket: Vec<String>,
+    /// How many of `orphaned_in_bucket` were deleted, if `delete_orphans` was set.
+    pub deleted: usize,
+}
+
+/// Escapes `%`, `_` and the escape character itself so `prefix` can be turned
+/// into a `LIKE ... ESCAPE '\'` pattern without its literal characters (most
+/// commonly `_`, as in crate names like `serde_json`) being treated as LIKE
+/// wildcards.
+fn escape_like(prefix: &str) -> String {
+    prefix
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+/// Cross-references the `files` table against `backend`'s bucket listing
+/// under `prefix`, to catch rows whose upload never landed and objects left
+/// behind after a crate was yanked or rebuilt.
+///
+/// When `delete_orphans` is set, bucket objects with no matching database row
+/// are deleted; otherwise they're only reported.
+pub fn check_s3_consistency(
+    conn: &Connection,
+    backend: &dyn StorageBackend,
+    prefix: &str,
+    delete_orphans: bool,
+) -> Result<ConsistencyReport> {
+    use std::collections::HashSet;
+
+    let rows = conn.query(
+        "SELECT path, content = E'in-s3' FROM files WHERE path LIKE $1 ESCAPE '\\'",
+        &[&format!("{}%", escape_like(prefix))],
+    )?;
+    let mut db_paths: HashSet<String> = HashSet::new();
+    let mut in_s3_paths: HashSet<String> = HashSet::new();
+    for row in &rows {
+        let path: String = row.get(0);
+        let in_s3: bool = row.get(1);
+        if in_s3 {
+            in_s3_paths.insert(path.clone());
         }
-        Err(e) => {
-            panic!("results err: {:?}", e);
+        db_paths.insert(path);
+    }
+
+    let mut bucket_paths = HashSet::new();
+    let mut continuation_token = None;
+    loop {
+        let page = backend.list_objects(prefix, continuation_token.as_deref())?;
+        bucket_paths.extend(page.keys);
+
+        continuation_token = page.continuation_token;
+        if continuation_token.is_none() {
+            break;
         }
     }
 
-    trans.commit()?;
+    let missing_from_bucket: Vec<String> = in_s3_paths.difference(&bucket_paths).cloned().collect();
+    let orphaned_in_bucket: Vec<String> = bucket_paths.difference(&db_paths).cloned().collect();
 
-    Ok(())
+    let mut deleted = 0;
+    if delete_orphans {
+        for path in &orphaned_in_bucket {
+            backend.delete(path)?;
+            deleted += 1;
+        }
+    }
+
+    Ok(ConsistencyReport {
+        missing_from_bucket,
+        orphaned_in_bucket,
+        deleted,
+    })
 }
 
 #[cfg(test)]
@@ -0,0 +1,95 @@
+//! Stores blobs as plain files under a directory on local disk.
+//!
+//! Mostly useful for development, and for self-hosted instances that would
+//! rather not run Postgres or an object store just to serve documentation.
+
+use super::{Blob, StorageBackend};
+use crate::error::Result;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+pub struct LocalBackend {
+    root: PathBuf,
+}
+
+impl LocalBackend {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        LocalBackend { root: root.into() }
+    }
+}
+
+impl StorageBackend for LocalBackend {
+    fn get(&self, path: &str) -> Result<Blob> {
+        let full_path = self.root.join(path);
+
+        let mut content = Vec::new();
+        fs::File::open(&full_path)?.read_to_end(&mut content)?;
+
+        let modified = fs::metadata(&full_path)?.modified()?;
+        let since_epoch = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+
+        Ok(Blob {
+            path: path.to_owned(),
+            mime: guess_mime(&full_path),
+            date_updated: time::Timespec::new(since_epoch.as_secs() as i64, 0),
+            content,
+        })
+    }
+
+    fn put(&self, path: &str, _mime: &str, content: &[u8]) -> Result<()> {
+        let full_path = self.root.join(path);
+        if let Some(parent) = full_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::File::create(&full_path)?.write_all(content)?;
+        Ok(())
+    }
+
+    fn exists(&self, path: &str) -> Result<bool> {
+        Ok(self.root.join(path).is_file())
+    }
+
+    fn put_stream(&self, path: &str, _mime: &str, mut reader: Box<dyn Read + Send>) -> Result<()> {
+        let full_path = self.root.join(path);
+        if let Some(parent) = full_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = fs::File::create(&full_path)?;
+        std::io::copy(&mut reader, &mut file)?;
+        Ok(())
+    }
+
+    fn delete(&self, path: &str) -> Result<()> {
+        match fs::remove_file(self.root.join(path)) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            other => Ok(other?),
+        }
+    }
+
+    fn get_stream(&self, path: &str) -> Result<(super::BlobMetadata, Box<dyn Read>)> {
+        let full_path = self.root.join(path);
+        let modified = fs::metadata(&full_path)?.modified()?;
+        let since_epoch = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+
+        let metadata = super::BlobMetadata {
+            path: path.to_owned(),
+            mime: guess_mime(&full_path),
+            date_updated: time::Timespec::new(since_epoch.as_secs() as i64, 0),
+        };
+
+        Ok((metadata, Box::new(fs::File::open(&full_path)?)))
+    }
+}
+
+fn guess_mime(path: &Path) -> String {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("css") => "text/css",
+        Some("js") => "application/javascript",
+        Some("html") => "text/html",
+        Some("json") => "application/json",
+        _ => "application/octet-stream",
+    }
+    .to_owned()
+}
@@ -0,0 +1,105 @@
+//! Pluggable storage backends for the blobs that make up rendered documentation.
+//!
+//! cratesfyi originally stored every file directly in Postgres. As the corpus
+//! grew past several million small files it became useful to offload storage
+//! onto an object store while keeping Postgres available as a fallback, so
+//! the rest of the app is written against this trait rather than against a
+//! specific backend.
+
+pub mod local;
+pub mod postgres;
+pub mod s3;
+
+use crate::error::Result;
+use failure::err_msg;
+use std::io::Read;
+
+pub struct Blob {
+    pub path: String,
+    pub mime: String,
+    pub date_updated: time::Timespec,
+    pub content: Vec<u8>,
+}
+
+/// The same fields as [`Blob`], minus the content, for callers that want to
+/// stream the content separately instead of holding it all in memory.
+pub struct BlobMetadata {
+    pub path: String,
+    pub mime: String,
+    pub date_updated: time::Timespec,
+}
+
+/// Either the blob itself, or a URL the web layer should redirect the client
+/// to instead of serving the content directly.
+pub enum BlobOrRedirect {
+    Blob(Blob),
+    Redirect(String),
+}
+
+/// One page of the paths stored under a prefix.
+pub struct ObjectListing {
+    pub keys: Vec<String>,
+    /// Pass this to the next call to `list_objects` to fetch the next page.
+    /// `None` means this was the last page.
+    pub continuation_token: Option<String>,
+}
+
+/// A place blobs can be stored and retrieved from by path.
+///
+/// Implementations are free to interpret `path` however suits them (an S3
+/// object key, a relative filesystem path, ...); callers should treat it as
+/// an opaque identifier that's shared across all backends.
+pub trait StorageBackend {
+    fn get(&self, path: &str) -> Result<Blob>;
+    fn put(&self, path: &str, mime: &str, content: &[u8]) -> Result<()>;
+    fn exists(&self, path: &str) -> Result<bool>;
+
+    /// Returns a URL the client can be redirected to instead of having the
+    /// content streamed through the app, if this backend supports it (e.g. a
+    /// presigned S3 URL). Backends that don't support this, like the
+    /// database and local filesystem backends, return `None`.
+    fn get_public_url(&self, path: &str) -> Option<String> {
+        let _ = path;
+        None
+    }
+
+    /// Like [`put`](StorageBackend::put), but streams `reader` into the
+    /// backend instead of requiring the whole file in memory up front.
+    /// Backends that can't avoid buffering (like Postgres, which needs the
+    /// whole blob to bind as a single query parameter) can rely on the
+    /// default implementation.
+    fn put_stream(&self, path: &str, mime: &str, mut reader: Box<dyn Read + Send>) -> Result<()> {
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content)?;
+        self.put(path, mime, &content)
+    }
+
+    /// Like [`get`](StorageBackend::get), but returns the content as a boxed
+    /// `Read` instead of a fully buffered `Vec<u8>`, so callers (e.g. the web
+    /// layer) can pipe it straight through without an extra in-memory copy.
+    fn get_stream(&self, path: &str) -> Result<(BlobMetadata, Box<dyn Read>)> {
+        let blob = self.get(path)?;
+        Ok((
+            BlobMetadata {
+                path: blob.path,
+                mime: blob.mime,
+                date_updated: blob.date_updated,
+            },
+            Box::new(std::io::Cursor::new(blob.content)),
+        ))
+    }
+
+    /// Removes the blob at `path`, if it exists.
+    fn delete(&self, path: &str) -> Result<()> {
+        let _ = path;
+        Err(err_msg("this backend does not support deleting blobs"))
+    }
+
+    /// Lists up to one page of the paths stored under `prefix`. Pass the
+    /// previous call's `continuation_token` to fetch subsequent pages.
+    /// Backends that don't implement listing return an error.
+    fn list_objects(&self, prefix: &str, continuation_token: Option<&str>) -> Result<ObjectListing> {
+        let _ = (prefix, continuation_token);
+        Err(err_msg("this backend does not support listing objects"))
+    }
+}
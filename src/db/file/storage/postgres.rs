@@ -0,0 +1,99 @@
+//! Stores blobs directly in the `files` table.
+//!
+//! This is the original storage backend and remains the default for
+//! self-hosted instances that would rather not run a separate object store.
+
+use super::{Blob, StorageBackend};
+use crate::error::Result;
+use failure::err_msg;
+use postgres::Connection;
+
+pub struct PostgresBackend<'a> {
+    conn: &'a Connection,
+    /// Where to look up rows that [`move_to_s3`](crate::db::file::move_to_s3)
+    /// has already migrated out of the database, marked by the `in-s3`
+    /// sentinel in the `content` column.
+    s3_fallback: Option<&'a dyn StorageBackend>,
+}
+
+impl<'a> PostgresBackend<'a> {
+    pub fn new(conn: &'a Connection) -> Self {
+        PostgresBackend {
+            conn,
+            s3_fallback: None,
+        }
+    }
+
+    /// Like [`new`](PostgresBackend::new), but falls back to `s3_fallback`
+    /// for rows already migrated out of the database, so a deployment
+    /// mid-migration (or permanently split between the two) can still serve
+    /// every file through a single backend.
+    pub fn with_s3_fallback(conn: &'a Connection, s3_fallback: &'a dyn StorageBackend) -> Self {
+        PostgresBackend {
+            conn,
+            s3_fallback: Some(s3_fallback),
+        }
+    }
+}
+
+impl<'a> StorageBackend for PostgresBackend<'a> {
+    fn get(&self, path: &str) -> Result<Blob> {
+        let rows = self.conn.query(
+            "SELECT path, mime, date_updated, content
+                           FROM files
+                           WHERE path = $1",
+            &[&path],
+        )?;
+
+        let row = rows.iter().next().ok_or_else(|| err_msg("file not found"))?;
+
+        let content: Vec<u8> = row.get(3);
+        if content == b"in-s3" {
+            return self
+                .s3_fallback
+                .ok_or_else(|| err_msg("file was migrated to s3, but no s3 fallback is configured"))?
+                .get(path);
+        }
+
+        Ok(Blob {
+            path: row.get(0),
+            mime: row.get(1),
+            date_updated: row.get(2),
+            content,
+        })
+    }
+
+    fn put(&self, path: &str, mime: &str, content: &[u8]) -> Result<()> {
+        let rows = self
+            .conn
+            .query("SELECT COUNT(*) FROM files WHERE path = $1", &[&path])?;
+
+        if rows.get(0).get::<usize, i64>(0) == 0 {
+            self.conn.execute(
+                "INSERT INTO files (path, mime, content) VALUES ($1, $2, $3)",
+                &[&path, &mime, &content],
+            )?;
+        } else {
+            self.conn.execute(
+                "UPDATE files SET mime = $2, content = $3, date_updated = NOW() \
+                 WHERE path = $1",
+                &[&path, &mime, &content],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn exists(&self, path: &str) -> Result<bool> {
+        let rows = self
+            .conn
+            .query("SELECT COUNT(*) FROM files WHERE path = $1", &[&path])?;
+        Ok(rows.get(0).get::<usize, i64>(0) > 0)
+    }
+
+    fn delete(&self, path: &str) -> Result<()> {
+        self.conn
+            .execute("DELETE FROM files WHERE path = $1", &[&path])?;
+        Ok(())
+    }
+}
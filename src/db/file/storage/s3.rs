@@ -0,0 +1,513 @@
+//! Stores blobs in an S3-compatible object store.
+//!
+//! Any endpoint that speaks the S3 API works here (AWS itself, MinIO,
+//! Backblaze B2, Wasabi, Google Cloud Storage's S3 interop, ...); point
+//! `endpoint` and `region` at whichever provider you run and the rest of the
+//! app doesn't need to know the difference.
+//!
+//! Requests are signed with our own [`sigv4`] signer over a plain blocking
+//! HTTP client, rather than rusoto, so this backend doesn't need an async
+//! runtime.
+
+mod credentials;
+mod sigv4;
+
+use super::{Blob, BlobMetadata, ObjectListing, StorageBackend};
+use crate::error::Result;
+use failure::err_msg;
+use reqwest::blocking::{Body, Client, RequestBuilder};
+use reqwest::{Method, StatusCode};
+use std::io::Read;
+use std::time::Duration;
+
+/// Sent as `x-amz-content-sha256` for streamed requests, where the payload
+/// can't be hashed up front without buffering it. S3-compatible endpoints
+/// accept this in place of the real hash at the cost of not being able to
+/// verify the body matches what was signed.
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+/// How long a presigned GET URL stays valid for before the client has to ask
+/// docs.rs for a new one.
+const PRESIGNED_GET_EXPIRY: Duration = Duration::from_secs(60 * 5);
+
+#[derive(Clone)]
+pub struct S3Config {
+    pub bucket: String,
+    pub region: String,
+    /// e.g. `https://s3.us-west-1.amazonaws.com`, or a MinIO/Backblaze
+    /// B2/Wasabi/GCS endpoint for self-hosted instances.
+    pub endpoint: String,
+}
+
+pub struct S3Backend {
+    http: Client,
+    config: S3Config,
+    /// `None` means we sign nothing and send anonymous/unsigned requests,
+    /// which only works against a public bucket.
+    credentials: Option<sigv4::Credentials>,
+}
+
+impl S3Backend {
+    pub fn new(config: S3Config, credentials: Option<sigv4::Credentials>) -> Self {
+        S3Backend {
+            http: Client::new(),
+            config,
+            credentials,
+        }
+    }
+
+    /// Builds a backend pointed at an arbitrary S3-compatible endpoint, e.g.
+    /// MinIO, Backblaze B2, Wasabi, or GCS's S3 interoperability API.
+    pub fn new_with_endpoint(
+        bucket: &str,
+        region: &str,
+        endpoint: &str,
+        credentials: Option<sigv4::Credentials>,
+    ) -> Self {
+        Self::new(
+            S3Config {
+                bucket: bucket.to_owned(),
+                region: region.to_owned(),
+                endpoint: endpoint.to_owned(),
+            },
+            credentials,
+        )
+    }
+
+    /// Builds a backend using the standard AWS credential resolution chain:
+    /// environment variables, the shared credentials/config files, then
+    /// EC2/ECS instance metadata. Falls back to sending unsigned requests,
+    /// which only works against a public bucket, if none of those resolve.
+    pub fn discover(bucket: &str, endpoint: Option<String>, profile: &str) -> Self {
+        let resolved = credentials::resolve(profile);
+        let region = resolved.region.unwrap_or_else(|| "us-west-1".to_owned());
+        let endpoint =
+            endpoint.unwrap_or_else(|| format!("https://s3.{}.amazonaws.com", region));
+
+        Self::new(
+            S3Config {
+                bucket: bucket.to_owned(),
+                region,
+                endpoint,
+            },
+            resolved.credentials,
+        )
+    }
+
+    fn object_url(&self, path: &str) -> String {
+        format!(
+            "{}/{}/{}",
+            self.config.endpoint.trim_end_matches('/'),
+            self.config.bucket,
+            path
+        )
+    }
+
+    fn object_path(&self, path: &str) -> String {
+        format!("/{}/{}", self.config.bucket, path)
+    }
+
+    /// The `Host` header to sign and send, including the port when the
+    /// endpoint specifies a non-default one.
+    fn host_header(&self) -> Result<String> {
+        let url = url::Url::parse(&self.config.endpoint)?;
+        let host = url
+            .host_str()
+            .ok_or_else(|| err_msg("S3 endpoint has no host"))?;
+        Ok(match url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_owned(),
+        })
+    }
+
+    /// Builds a signed request for `path`, with everything set except the
+    /// body. `payload_hash` must be the hex SHA-256 of whatever body the
+    /// caller is about to attach (or [`UNSIGNED_PAYLOAD`] if it can't be
+    /// computed up front, e.g. because the body is being streamed).
+    fn signed_request(
+        &self,
+        method: Method,
+        path: &str,
+        payload_hash: &str,
+        content_type: Option<&str>,
+    ) -> Result<RequestBuilder> {
+        let host = self.host_header()?;
+        let now = time::now_utc().to_timespec();
+        let amz_date = sigv4::format_amz_date(now);
+        let date_stamp = &amz_date[..8];
+
+        let mut headers: Vec<(String, String)> = vec![
+            ("host".to_owned(), host.clone()),
+            ("x-amz-content-sha256".to_owned(), payload_hash.to_owned()),
+            ("x-amz-date".to_owned(), amz_date.clone()),
+        ];
+        if let Some(ct) = content_type {
+            headers.push(("content-type".to_owned(), ct.to_owned()));
+        }
+        headers.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut req = self
+            .http
+            .request(method.clone(), &self.object_url(path))
+            .header("host", &host)
+            .header("x-amz-content-sha256", payload_hash)
+            .header("x-amz-date", &amz_date);
+
+        if let Some(ct) = content_type {
+            req = req.header("content-type", ct);
+        }
+
+        if let Some(credentials) = &self.credentials {
+            let canonical = sigv4::CanonicalRequest {
+                method: method.as_str(),
+                path: &self.object_path(path),
+                query: &[],
+                headers: &headers,
+                payload_hash,
+            };
+            let signature =
+                sigv4::sign(&canonical, credentials, &self.config.region, date_stamp, &amz_date);
+            let authorization = format!(
+                "AWS4-HMAC-SHA256 Credential={access_key}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+                access_key = credentials.access_key_id,
+                scope = sigv4::credential_scope(date_stamp, &self.config.region),
+                signed_headers = headers
+                    .iter()
+                    .map(|(k, _)| k.as_str())
+                    .collect::<Vec<_>>()
+                    .join(";"),
+                signature = signature,
+            );
+            req = req.header("authorization", authorization);
+            if let Some(token) = &credentials.session_token {
+                req = req.header("x-amz-security-token", token);
+            }
+        }
+
+        Ok(req)
+    }
+
+    fn send(
+        &self,
+        method: Method,
+        path: &str,
+        body: &[u8],
+        content_type: Option<&str>,
+    ) -> Result<reqwest::blocking::Response> {
+        let payload_hash = sigv4::payload_hash(body);
+        let req = self.signed_request(method, path, &payload_hash, content_type)?;
+        Ok(req.body(body.to_vec()).send()?)
+    }
+
+    /// Like [`send`](Self::send), but streams `reader` into the request body
+    /// instead of requiring it to already be in memory. Signed with
+    /// [`UNSIGNED_PAYLOAD`], since the body's hash can't be known up front
+    /// without buffering it.
+    fn send_streaming(
+        &self,
+        method: Method,
+        path: &str,
+        reader: Box<dyn Read + Send>,
+        content_type: Option<&str>,
+    ) -> Result<reqwest::blocking::Response> {
+        let req = self.signed_request(method, path, UNSIGNED_PAYLOAD, content_type)?;
+        Ok(req.body(Body::new(reader)).send()?)
+    }
+
+    /// Produces a time-limited URL the client can fetch `path` from directly,
+    /// without the request going through this app at all.
+    pub fn presign_get(&self, path: &str, expires_in: Duration) -> Result<String> {
+        let credentials = self
+            .credentials
+            .as_ref()
+            .ok_or_else(|| err_msg("cannot presign a URL without credentials"))?;
+
+        let host = self.host_header()?;
+        let now = time::now_utc().to_timespec();
+        let amz_date = sigv4::format_amz_date(now);
+        let date_stamp = &amz_date[..8];
+        let scope = sigv4::credential_scope(date_stamp, &self.config.region);
+
+        let mut query = vec![
+            (
+                "X-Amz-Algorithm".to_owned(),
+                "AWS4-HMAC-SHA256".to_owned(),
+            ),
+            (
+                "X-Amz-Credential".to_owned(),
+                format!("{}/{}", credentials.access_key_id, scope),
+            ),
+            ("X-Amz-Date".to_owned(), amz_date.clone()),
+            (
+                "X-Amz-Expires".to_owned(),
+                expires_in.as_secs().to_string(),
+            ),
+            ("X-Amz-SignedHeaders".to_owned(), "host".to_owned()),
+        ];
+        query.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let headers = vec![("host".to_owned(), host)];
+        let canonical = sigv4::CanonicalRequest {
+            method: "GET",
+            path: &self.object_path(path),
+            query: &query,
+            headers: &headers,
+            payload_hash: "UNSIGNED-PAYLOAD",
+        };
+        let signature = sigv4::sign(&canonical, credentials, &self.config.region, date_stamp, &amz_date);
+
+        let query_string = query
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        Ok(format!(
+            "{}?{}&X-Amz-Signature={}",
+            self.object_url(path),
+            query_string,
+            signature
+        ))
+    }
+
+    /// Lists up to one page of the keys stored under `prefix`, via a
+    /// `ListObjectsV2` request.
+    fn list_objects_page(
+        &self,
+        prefix: &str,
+        continuation_token: Option<&str>,
+    ) -> Result<ObjectListing> {
+        let mut query: Vec<(String, String)> = vec![
+            ("list-type".to_owned(), "2".to_owned()),
+            ("prefix".to_owned(), prefix.to_owned()),
+        ];
+        if let Some(token) = continuation_token {
+            query.push(("continuation-token".to_owned(), token.to_owned()));
+        }
+        query.sort_by(|a, b| a.0.cmp(&b.0));
+
+        // `continuation-token` is base64 and routinely contains `+`, `/` and
+        // `=`; sent raw in the query string a `+` is decoded as a space and
+        // the token is misread, so every page past the first fails.
+        let query_string = query
+            .iter()
+            .map(|(k, v)| format!("{}={}", sigv4::uri_encode(k, true), sigv4::uri_encode(v, true)))
+            .collect::<Vec<_>>()
+            .join("&");
+        let url = format!(
+            "{}/{}?{}",
+            self.config.endpoint.trim_end_matches('/'),
+            self.config.bucket,
+            query_string
+        );
+        let canonical_path = format!("/{}", self.config.bucket);
+        let payload_hash = sigv4::payload_hash(b"");
+
+        let body = self
+            .signed_bucket_request(Method::GET, &url, &canonical_path, &query, &payload_hash)?
+            .send()?
+            .error_for_status()?
+            .text()?;
+
+        parse_list_bucket_result(&body)
+    }
+
+    /// Like [`signed_request`](Self::signed_request), but for operations
+    /// against the bucket itself (e.g. listing) rather than a single object.
+    fn signed_bucket_request(
+        &self,
+        method: Method,
+        url: &str,
+        canonical_path: &str,
+        query: &[(String, String)],
+        payload_hash: &str,
+    ) -> Result<RequestBuilder> {
+        let host = self.host_header()?;
+        let now = time::now_utc().to_timespec();
+        let amz_date = sigv4::format_amz_date(now);
+        let date_stamp = &amz_date[..8];
+
+        let headers: Vec<(String, String)> = vec![
+            ("host".to_owned(), host.clone()),
+            ("x-amz-content-sha256".to_owned(), payload_hash.to_owned()),
+            ("x-amz-date".to_owned(), amz_date.clone()),
+        ];
+
+        let mut req = self
+            .http
+            .request(method.clone(), url)
+            .header("host", &host)
+            .header("x-amz-content-sha256", payload_hash)
+            .header("x-amz-date", &amz_date);
+
+        if let Some(credentials) = &self.credentials {
+            let canonical = sigv4::CanonicalRequest {
+                method: method.as_str(),
+                path: canonical_path,
+                query,
+                headers: &headers,
+                payload_hash,
+            };
+            let signature = sigv4::sign(
+                &canonical,
+                credentials,
+                &self.config.region,
+                date_stamp,
+                &amz_date,
+            );
+            let authorization = format!(
+                "AWS4-HMAC-SHA256 Credential={access_key}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+                access_key = credentials.access_key_id,
+                scope = sigv4::credential_scope(date_stamp, &self.config.region),
+                signed_headers = headers
+                    .iter()
+                    .map(|(k, _)| k.as_str())
+                    .collect::<Vec<_>>()
+                    .join(";"),
+                signature = signature,
+            );
+            req = req.header("authorization", authorization);
+        }
+
+        Ok(req)
+    }
+}
+
+/// Parses the `Last-Modified` response header S3 sends on every object
+/// fetch, falling back to now if it's missing or malformed (which shouldn't
+/// happen against a real S3-compatible endpoint).
+fn parse_last_modified(headers: &reqwest::header::HeaderMap) -> time::Timespec {
+    headers
+        .get(reqwest::header::LAST_MODIFIED)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| time::strptime(v, "%a, %d %b %Y %H:%M:%S %Z").ok())
+        .map(|tm| tm.to_timespec())
+        .unwrap_or_else(|| time::now_utc().to_timespec())
+}
+
+/// Pulls `<Key>` and `<NextContinuationToken>` out of a `ListObjectsV2`
+/// response body.
+fn parse_list_bucket_result(xml: &str) -> Result<ObjectListing> {
+    use quick_xml::events::Event;
+    use quick_xml::Reader;
+
+    let mut reader = Reader::from_str(xml);
+    reader.trim_text(true);
+
+    let mut buf = Vec::new();
+    let mut tag_stack: Vec<String> = Vec::new();
+    let mut keys = Vec::new();
+    let mut continuation_token = None;
+
+    loop {
+        match reader.read_event(&mut buf)? {
+            Event::Start(e) => tag_stack.push(String::from_utf8_lossy(e.name()).into_owned()),
+            Event::End(_) => {
+                tag_stack.pop();
+            }
+            Event::Text(e) => {
+                let text = e.unescape_and_decode(&reader)?;
+                match tag_stack.last().map(|s| s.as_str()) {
+                    Some("Key") => keys.push(text),
+                    Some("NextContinuationToken") => continuation_token = Some(text),
+                    _ => {}
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+
+    Ok(ObjectListing {
+        keys,
+        continuation_token,
+    })
+}
+
+impl StorageBackend for S3Backend {
+    fn get(&self, path: &str) -> Result<Blob> {
+        let res = self.send(Method::GET, path, b"", None)?;
+        if res.status() == StatusCode::NOT_FOUND {
+            return Err(err_msg("file not found"));
+        }
+        let res = res.error_for_status()?;
+
+        let mime = res
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("application/octet-stream")
+            .to_owned();
+        let date_updated = parse_last_modified(res.headers());
+        let content = res.bytes()?.to_vec();
+
+        Ok(Blob {
+            path: path.to_owned(),
+            mime,
+            date_updated,
+            content,
+        })
+    }
+
+    fn put(&self, path: &str, mime: &str, content: &[u8]) -> Result<()> {
+        self.send(Method::PUT, path, content, Some(mime))?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    fn exists(&self, path: &str) -> Result<bool> {
+        let res = self.send(Method::HEAD, path, b"", None)?;
+        match res.status() {
+            StatusCode::OK => Ok(true),
+            StatusCode::NOT_FOUND => Ok(false),
+            status => Err(err_msg(format!(
+                "unexpected status {} checking for {}",
+                status, path
+            ))),
+        }
+    }
+
+    fn get_public_url(&self, path: &str) -> Option<String> {
+        self.presign_get(path, PRESIGNED_GET_EXPIRY).ok()
+    }
+
+    fn put_stream(&self, path: &str, mime: &str, reader: Box<dyn Read + Send>) -> Result<()> {
+        self.send_streaming(Method::PUT, path, reader, Some(mime))?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    fn get_stream(&self, path: &str) -> Result<(BlobMetadata, Box<dyn Read>)> {
+        let res = self.send(Method::GET, path, b"", None)?;
+        if res.status() == StatusCode::NOT_FOUND {
+            return Err(err_msg("file not found"));
+        }
+        let res = res.error_for_status()?;
+
+        let mime = res
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("application/octet-stream")
+            .to_owned();
+
+        let metadata = BlobMetadata {
+            path: path.to_owned(),
+            mime,
+            date_updated: parse_last_modified(res.headers()),
+        };
+
+        Ok((metadata, Box::new(res)))
+    }
+
+    fn delete(&self, path: &str) -> Result<()> {
+        self.send(Method::DELETE, path, b"", None)?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    fn list_objects(&self, prefix: &str, continuation_token: Option<&str>) -> Result<ObjectListing> {
+        self.list_objects_page(prefix, continuation_token)
+    }
+}
@@ -0,0 +1,273 @@
+//! Resolves AWS credentials the same way the official SDKs do, so deployments
+//! using IAM roles or named profiles work without exporting secrets into the
+//! process environment.
+//!
+//! Tried in order: environment variables, the shared credentials/config
+//! files (`~/.aws/credentials` and `~/.aws/config`), then the EC2/ECS
+//! instance-metadata endpoint. If none of those yield anything, the caller
+//! falls back to unsigned requests (or DB-only storage).
+
+use super::sigv4::Credentials;
+use std::collections::HashMap;
+use std::time::Duration;
+
+pub struct ResolvedCredentials {
+    pub credentials: Option<Credentials>,
+    pub region: Option<String>,
+}
+
+/// Resolves credentials for `profile` (used only by the shared-file lookup;
+/// ignored by the other sources). `profile` is usually `"default"`.
+pub fn resolve(profile: &str) -> ResolvedCredentials {
+    if let Some(resolved) = from_environment() {
+        return resolved;
+    }
+    if let Some(resolved) = from_shared_files(profile) {
+        return resolved;
+    }
+    if let Some(resolved) = from_instance_metadata() {
+        return resolved;
+    }
+    ResolvedCredentials {
+        credentials: None,
+        region: None,
+    }
+}
+
+fn from_environment() -> Option<ResolvedCredentials> {
+    let access_key_id = std::env::var("AWS_ACCESS_KEY_ID").ok()?;
+    let secret_access_key = std::env::var("AWS_SECRET_ACCESS_KEY").ok()?;
+    let session_token = std::env::var("AWS_SESSION_TOKEN").ok();
+    let region = std::env::var("AWS_REGION")
+        .or_else(|_| std::env::var("AWS_DEFAULT_REGION"))
+        .ok();
+
+    Some(ResolvedCredentials {
+        credentials: Some(Credentials {
+            access_key_id,
+            secret_access_key,
+            session_token,
+        }),
+        region,
+    })
+}
+
+fn from_shared_files(profile: &str) -> Option<ResolvedCredentials> {
+    let home = dirs::home_dir()?;
+
+    let creds_section = std::fs::read_to_string(home.join(".aws/credentials"))
+        .ok()
+        .and_then(|contents| parse_ini(&contents).remove(profile));
+    let config_section = std::fs::read_to_string(home.join(".aws/config"))
+        .ok()
+        .and_then(|contents| {
+            // profiles are named `[<name>]` in credentials, but in config
+            // only the default profile is `[default]`; every other one is
+            // `[profile <name>]`.
+            let config_profile_name = if profile == "default" {
+                "default".to_owned()
+            } else {
+                format!("profile {}", profile)
+            };
+            parse_ini(&contents).remove(&config_profile_name)
+        });
+
+    let creds_section = creds_section?;
+    let access_key_id = creds_section.get("aws_access_key_id")?.clone();
+    let secret_access_key = creds_section.get("aws_secret_access_key")?.clone();
+    let session_token = creds_section.get("aws_session_token").cloned();
+    let region = config_section.and_then(|s| s.get("region").cloned());
+
+    Some(ResolvedCredentials {
+        credentials: Some(Credentials {
+            access_key_id,
+            secret_access_key,
+            session_token,
+        }),
+        region,
+    })
+}
+
+/// A very small INI parser, just enough for `~/.aws/credentials` and
+/// `~/.aws/config`: `[section]` headers and `key = value` pairs, `#`/`;`
+/// comments, no nesting or multi-line values.
+fn parse_ini(contents: &str) -> HashMap<String, HashMap<String, String>> {
+    let mut sections = HashMap::new();
+    let mut current = String::new();
+
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+
+        if line.starts_with('[') && line.ends_with(']') {
+            current = line[1..line.len() - 1].trim().to_owned();
+            sections.entry(current.clone()).or_insert_with(HashMap::new);
+            continue;
+        }
+
+        if let Some(eq) = line.find('=') {
+            let key = line[..eq].trim().to_lowercase();
+            let value = line[eq + 1..].trim().to_owned();
+            sections
+                .entry(current.clone())
+                .or_insert_with(HashMap::new)
+                .insert(key, value);
+        }
+    }
+
+    sections
+}
+
+const IMDS_HOST: &str = "169.254.169.254";
+/// Host that serves ECS task-role credentials; distinct from the EC2 IMDS
+/// host above.
+const ECS_CREDENTIALS_HOST: &str = "169.254.170.2";
+
+/// Fetches role credentials from the EC2/ECS instance-metadata service.
+/// Returns `None` quickly (short timeout) when not running on EC2/ECS.
+fn from_instance_metadata() -> Option<ResolvedCredentials> {
+    let client = reqwest::blocking::Client::builder()
+        .timeout(Duration::from_millis(500))
+        .build()
+        .ok()?;
+
+    // ECS tasks get their credentials from a relative URI under a different
+    // metadata host than the EC2 IAM role endpoint.
+    if let Ok(relative_uri) = std::env::var("AWS_CONTAINER_CREDENTIALS_RELATIVE_URI") {
+        let url = format!("http://{}{}", ECS_CREDENTIALS_HOST, relative_uri);
+        if let Ok(body) = client.get(&url).send().and_then(|r| r.text()) {
+            return credentials_from_json(&body);
+        }
+        return None;
+    }
+
+    // IMDSv2: fetch a token, then use it to read the attached role's
+    // credentials.
+    let token = client
+        .put(&format!("http://{}/latest/api/token", IMDS_HOST))
+        .header("X-aws-ec2-metadata-token-ttl-seconds", "30")
+        .send()
+        .ok()?
+        .text()
+        .ok()?;
+
+    let role_url = format!(
+        "http://{}/latest/meta-data/iam/security-credentials/",
+        IMDS_HOST
+    );
+    let role = client
+        .get(&role_url)
+        .header("X-aws-ec2-metadata-token", &token)
+        .send()
+        .ok()?
+        .text()
+        .ok()?;
+    let role = role.trim();
+    if role.is_empty() {
+        return None;
+    }
+
+    let body = client
+        .get(&format!("{}{}", role_url, role))
+        .header("X-aws-ec2-metadata-token", &token)
+        .send()
+        .ok()?
+        .text()
+        .ok()?;
+
+    credentials_from_json(&body)
+}
+
+fn credentials_from_json(body: &str) -> Option<ResolvedCredentials> {
+    let json = rustc_serialize::json::Json::from_str(body).ok()?;
+    let object = json.as_object()?;
+
+    let access_key_id = object.get("AccessKeyId")?.as_string()?.to_owned();
+    let secret_access_key = object.get("SecretAccessKey")?.as_string()?.to_owned();
+    let session_token = object
+        .get("Token")
+        .and_then(|t| t.as_string())
+        .map(|t| t.to_owned());
+
+    Some(ResolvedCredentials {
+        credentials: Some(Credentials {
+            access_key_id,
+            secret_access_key,
+            session_token,
+        }),
+        region: None,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_ini_reads_default_and_named_sections() {
+        let contents = "\
+[default]
+aws_access_key_id = AKIDEXAMPLE
+aws_secret_access_key = secret
+
+[profile other]
+aws_access_key_id = AKIDOTHER
+region = us-west-2
+";
+        let sections = parse_ini(contents);
+
+        let default = sections.get("default").unwrap();
+        assert_eq!(default.get("aws_access_key_id").unwrap(), "AKIDEXAMPLE");
+        assert_eq!(default.get("aws_secret_access_key").unwrap(), "secret");
+
+        let other = sections.get("profile other").unwrap();
+        assert_eq!(other.get("aws_access_key_id").unwrap(), "AKIDOTHER");
+        assert_eq!(other.get("region").unwrap(), "us-west-2");
+    }
+
+    #[test]
+    fn parse_ini_ignores_comments_and_blank_lines() {
+        let contents = "\
+# a leading comment
+[default]
+; a semicolon comment
+region = us-east-1
+
+";
+        let sections = parse_ini(contents);
+        assert_eq!(sections.get("default").unwrap().get("region").unwrap(), "us-east-1");
+    }
+
+    #[test]
+    fn credentials_from_json_parses_instance_metadata_response() {
+        let body = r#"{
+            "Code": "Success",
+            "AccessKeyId": "ASIAEXAMPLE",
+            "SecretAccessKey": "secret",
+            "Token": "token-value",
+            "Expiration": "2026-01-01T00:00:00Z"
+        }"#;
+
+        let resolved = credentials_from_json(body).unwrap();
+        let credentials = resolved.credentials.unwrap();
+        assert_eq!(credentials.access_key_id, "ASIAEXAMPLE");
+        assert_eq!(credentials.secret_access_key, "secret");
+        assert_eq!(credentials.session_token.as_deref(), Some("token-value"));
+    }
+
+    #[test]
+    fn credentials_from_json_allows_missing_token() {
+        let body = r#"{"AccessKeyId": "ASIAEXAMPLE", "SecretAccessKey": "secret"}"#;
+
+        let resolved = credentials_from_json(body).unwrap();
+        assert!(resolved.credentials.unwrap().session_token.is_none());
+    }
+
+    #[test]
+    fn credentials_from_json_rejects_incomplete_or_malformed_bodies() {
+        assert!(credentials_from_json("not json").is_none());
+        assert!(credentials_from_json(r#"{"AccessKeyId": "ASIAEXAMPLE"}"#).is_none());
+    }
+}